@@ -11,9 +11,13 @@ use std::collections::HashSet;
 use orderedvec::OrderedVec;
 use curses::*;
 use query::Query;
-use util::eventbox::EventBox;
+use crossbeam_channel::Sender;
 use event::Event;
 use std::mem;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthChar;
+use theme::{ColorTheme, ColorRole};
+use screen::{Screen, Row, RowBuilder, CursorPos};
 
 // The whole screen is:
 //
@@ -32,7 +36,7 @@ use std::mem;
 //
 
 pub struct Model {
-    eb: Arc<EventBox<Event>>,
+    tx: Sender<Event>,
     pub query: Query,
 
     num_matched: u64,
@@ -53,14 +57,15 @@ pub struct Model {
 
     tabstop: usize,
     curses: Curses,
+    theme: ColorTheme,
 }
 
 impl Model {
-    pub fn new(eb: Arc<EventBox<Event>>, curses: Curses) -> Self {
+    pub fn new(tx: Sender<Event>, curses: Curses, theme: ColorTheme) -> Self {
         let (max_y, max_x) = curses.get_maxyx();
 
         Model {
-            eb: eb,
+            tx: tx,
             query: Query::new(),
             num_matched: 0,
             num_total: 0,
@@ -77,6 +82,7 @@ impl Model {
             height: (max_y - 2) as usize,
             tabstop: 8,
             curses: curses,
+            theme: theme,
         }
     }
 
@@ -103,107 +109,45 @@ impl Model {
         self.matched_items.borrow_mut().clear();
     }
 
-    pub fn print_query(&self) {
-        // > query
-        mv(self.max_y-1, 0);
-        addstr("> ");
-        addstr(&self.query.get_query());
-        mv(self.max_y-1, (self.query.pos+2) as i32);
+    // Builds a pure, backend-independent description of what the screen
+    // should look like; contains no ncurses calls, so it's straightforward
+    // to unit-test the layout math without a terminal.
+    pub fn render(&self) -> Screen {
+        let mut screen = Screen::new(self.max_y as usize);
+        self.render_items(&mut screen);
+        self.render_info(&mut screen);
+        self.render_query(&mut screen);
+        screen
     }
 
-    pub fn print_info(&self) {
-        mv(self.max_y-2, 0);
-        addstr(format!("  {}/{}{} ", self.num_matched, self.num_total,
-                       if self.processed_percentage == 100 {"".to_string()} else {format!("({}%)", self.processed_percentage)},
-                       ).as_str());
+    fn render_query(&self, screen: &mut Screen) {
+        screen.rows[(self.max_y-1) as usize] = build_query_row(&self.query.get_query());
+        screen.cursor = Some(CursorPos { y: self.max_y-1, x: (self.query.pos+2) as i32 });
     }
 
-    pub fn print_items(&self) {
+    fn render_info(&self, screen: &mut Screen) {
+        screen.rows[(self.max_y-2) as usize] = build_info_row(self.num_matched, self.num_total, self.processed_percentage);
+    }
+
+    fn render_items(&self, screen: &mut Screen) {
         let mut matched_items = self.matched_items.borrow_mut();
         let item_start_pos = self.item_cursor - self.line_cursor;
 
         for i in 0..self.height {
             if let Some(matched) = matched_items.get(item_start_pos + i) {
-                mv((self.height - i - 1) as i32, 0);
-
                 let is_current_line = i == self.line_cursor;
-                let label = if is_current_line {">"} else {" "};
-                self.curses.cprint(label, COLOR_CURSOR, true);
-                self.print_item(matched, is_current_line);
-            } else {
-                break;
-            }
-        }
-    }
-
-    fn print_item(&self, matched: &MatchedItem, is_current: bool) {
-        let items = self.items.read().unwrap();
-        let ref item = items[matched.index];
-
-        let is_selected = self.selected_indics.contains(&matched.index);
-
-        if is_selected {
-            self.curses.cprint(">", COLOR_SELECTED, true);
-        } else {
-            self.curses.cprint(" ", if is_current {COLOR_CURRENT} else {COLOR_NORMAL}, false);
-        }
-
-        match matched.matched_range {
-            Some(MatchedRange::Chars(ref matched_indics)) => {
-                let matched_end_pos = if matched_indics.len() > 0 {
-                    matched_indics[matched_indics.len()-1]
-                } else {
-                    0
+                let is_selected = self.selected_indics.contains(&matched.index);
+                let text = self.items.read().unwrap()[matched.index].text.clone();
+                let matched_indics = match matched.matched_range {
+                    Some(MatchedRange::Chars(ref v)) => Some(v.as_slice()),
+                    _ => None,
                 };
 
-                let (text, mut idx) = reshape_string(&item.text.chars().collect::<Vec<char>>(),
-                                                     (self.max_x-3) as usize,
-                                                     self.hscroll_offset,
-                                                     matched_end_pos);
-                let mut matched_indics_iter = matched_indics.iter().peekable();
-
-                // skip indics
-                while let Some(&&index) = matched_indics_iter.peek() {
-                    if idx > index {
-                        let _ = matched_indics_iter.next();
-                    } else {
-                        break;
-                    }
-                }
-
-                for &ch in text.iter() {
-                    match matched_indics_iter.peek() {
-                        Some(&&index) if idx == index => {
-                            self.print_char(ch, COLOR_MATCHED, is_current);
-                            let _ = matched_indics_iter.next();
-                        }
-                        Some(_) | None => {
-                            self.print_char(ch, if is_current {COLOR_CURRENT} else {COLOR_NORMAL}, is_current)
-                        }
-                    }
-                    idx += 1;
-                }
-            }
-            Some(MatchedRange::Range(_, _)) => {
-                // pass
-            }
-            None => {
-                // pass
-            }
-        }
-    }
-
-    fn print_char(&self, ch: char, color: i16, is_bold: bool) {
-        if ch != '\t' {
-            self.curses.caddch(ch, color, is_bold);
-        } else {
-            // handle tabstop
-            let mut y = 0;
-            let mut x = 0;
-            getyx(stdscr, &mut y, &mut x);
-            let rest = (self.tabstop as i32) - (x-2)%(self.tabstop as i32);
-            for i in 0..rest {
-                self.curses.caddch(' ', color, is_bold);
+                let row = build_item_row(is_current_line, is_selected, &text, matched_indics,
+                                          self.hscroll_offset, self.max_x, self.tabstop);
+                screen.rows[self.height - i - 1] = row;
+            } else {
+                break;
             }
         }
     }
@@ -213,10 +157,7 @@ impl Model {
     }
 
     pub fn display(&self) {
-        erase();
-        self.print_items();
-        self.print_info();
-        self.print_query();
+        self.curses.draw(&self.render(), &self.theme);
     }
 
     // the terminal resizes, so we need to recalculate the margins.
@@ -234,10 +175,22 @@ impl Model {
     //============================================================================
     // Actions
 
+    // Every act_* below that edits the query funnels its notification through
+    // here instead of sending directly. `try_send` means a busy matcher (the
+    // channel is expected to be bounded) never blocks the UI thread on a
+    // keystroke; a dropped notification is harmless because the query text
+    // lives in `self.query`, not in the channel, so whichever EvQueryChange
+    // the matcher next drains still carries the query as of *that* send, and
+    // the matcher is expected to drain the channel down to the latest message
+    // before re-matching rather than processing every intermediate one.
+    fn notify_query_change(&self) {
+        let _ = self.tx.try_send(Event::EvQueryChange(self.query.get_query()));
+    }
+
     pub fn act_add_char(&mut self, ch: char) {
         let changed = self.query.add_char(ch);
         if changed {
-            self.eb.set(Event::EvQueryChange, Box::new(self.query.get_query()));
+            self.notify_query_change();
         }
     }
 
@@ -248,14 +201,14 @@ impl Model {
     pub fn act_backward_delete_char(&mut self) {
         let changed = self.query.backward_delete_char();
         if changed {
-            self.eb.set(Event::EvQueryChange, Box::new(self.query.get_query()));
+            self.notify_query_change();
         }
     }
 
     pub fn act_backward_kill_word(&mut self) {
         let changed = self.query.backward_kill_word();
         if changed {
-            self.eb.set(Event::EvQueryChange, Box::new(self.query.get_query()));
+            self.notify_query_change();
         }
     }
 
@@ -270,7 +223,7 @@ impl Model {
     pub fn act_delete_char(&mut self) {
         let changed = self.query.delete_char();
         if changed {
-            self.eb.set(Event::EvQueryChange, Box::new(self.query.get_query()));
+            self.notify_query_change();
         }
     }
 
@@ -293,21 +246,21 @@ impl Model {
     pub fn act_kill_line(&mut self) {
         let changed = self.query.kill_line();
         if changed {
-            self.eb.set(Event::EvQueryChange, Box::new(self.query.get_query()));
+            self.notify_query_change();
         }
     }
 
     pub fn act_kill_word(&mut self) {
         let changed = self.query.kill_word();
         if changed {
-            self.eb.set(Event::EvQueryChange, Box::new(self.query.get_query()));
+            self.notify_query_change();
         }
     }
 
     pub fn act_line_discard(&mut self) {
         let changed = self.query.line_discard();
         if changed {
-            self.eb.set(Event::EvQueryChange, Box::new(self.query.get_query()));
+            self.notify_query_change();
         }
     }
 
@@ -387,38 +340,151 @@ impl Model {
 //==============================================================================
 // helper functions
 
-// wide character will take two unit
-fn display_width(text: &[char]) -> usize {
-    text.iter()
-        .map(|c| {if c.len_utf8() > 1 {2} else {1}})
+// `build_info_row`/`build_query_row`/`build_item_row` are free functions, not
+// `Model` methods, so the layout math is directly testable without a `Model`.
+
+// Builds the info line's row ("  123/456(78%) ").
+fn build_info_row(num_matched: u64, num_total: u64, processed_percentage: u64) -> Row {
+    let mut row = RowBuilder::new();
+    let text = format!("  {}/{}{} ", num_matched, num_total,
+                       if processed_percentage == 100 {"".to_string()} else {format!("({}%)", processed_percentage)});
+    for ch in text.chars() {
+        row.push(&ch.to_string(), ColorRole::Normal, false, 1);
+    }
+    row.into_row()
+}
+
+// Builds the "> query" row.
+fn build_query_row(query: &str) -> Row {
+    let mut row = RowBuilder::new();
+    for ch in "> ".chars().chain(query.chars()) {
+        row.push(&ch.to_string(), ColorRole::Normal, false, 1);
+    }
+    row.into_row()
+}
+
+// Builds one item row: the current-line marker, the selection marker, then,
+// when `matched_indics` is given, the (possibly reshaped) highlighted item text.
+// With `matched_indics` of `None` the row holds only the two markers.
+fn build_item_row(is_current: bool, is_selected: bool, text: &str, matched_indics: Option<&[usize]>,
+                   hscroll_offset: usize, max_x: i32, tabstop: usize) -> Row {
+    let mut row = RowBuilder::new();
+
+    let label = if is_current {">"} else {" "};
+    row.push(label, ColorRole::Cursor, true, 1);
+
+    if is_selected {
+        row.push(">", ColorRole::Selected, true, 1);
+    } else {
+        let role = if is_current {ColorRole::Current} else {ColorRole::Normal};
+        row.push(" ", role, false, 1);
+    }
+
+    if let Some(matched_indics) = matched_indics {
+        let matched_end_pos = if !matched_indics.is_empty() {
+            matched_indics[matched_indics.len()-1]
+        } else {
+            0
+        };
+
+        let clusters: Vec<&str> = text.graphemes(true).collect();
+        let (shaped, mut idx) = reshape_string(&clusters, (max_x-3) as usize, hscroll_offset, matched_end_pos);
+        let mut matched_indics_iter = matched_indics.iter().peekable();
+
+        // skip indics
+        while let Some(&&index) = matched_indics_iter.peek() {
+            if idx > index {
+                let _ = matched_indics_iter.next();
+            } else {
+                break;
+            }
+        }
+
+        for cluster in shaped.iter() {
+            match matched_indics_iter.peek() {
+                Some(&&index) if idx == index => {
+                    push_cluster(&mut row, cluster, ColorRole::Matched, is_current, tabstop);
+                    let _ = matched_indics_iter.next();
+                }
+                Some(_) | None => {
+                    let role = if is_current {ColorRole::Current} else {ColorRole::Normal};
+                    push_cluster(&mut row, cluster, role, is_current, tabstop);
+                }
+            }
+            idx += 1;
+        }
+    }
+
+    row.into_row()
+}
+
+// `cluster` is a single extended grapheme cluster; it may contain more than one
+// scalar value (e.g. a base letter plus combining marks), so it is pushed as a
+// single cell that advances the row's column by the cluster's display width
+// rather than by its number of `char`s. A tab is expanded to spaces up to the
+// next tabstop.
+fn push_cluster(row: &mut RowBuilder, cluster: &str, role: ColorRole, is_bold: bool, tabstop: usize) {
+    if cluster != "\t" {
+        row.push(cluster, role, is_bold, cluster_width(cluster));
+    } else {
+        // handle tabstop
+        let rest = (tabstop as i32) - (row.col() as i32 - 2)%(tabstop as i32);
+        for _ in 0..rest {
+            row.push(" ", role, is_bold, 1);
+        }
+    }
+}
+
+// A grapheme cluster's terminal column width is the max East-Asian width of its
+// constituent scalar values: wide/fullwidth -> 2, zero-width/combining/control -> 0,
+// otherwise 1. This keeps e.g. "e" + U+0301 (combining acute accent) at width 1
+// instead of the 2 columns a per-`char` count would have produced.
+fn cluster_width(cluster: &str) -> usize {
+    if cluster == "\t" {
+        // `UnicodeWidthChar` has no notion of a tab stop and returns `None`, but a
+        // tab still occupies at least one column; `push_cluster` is what expands it
+        // to the actual tabstop-aware width when the row is built.
+        return 1;
+    }
+
+    cluster.chars()
+        .map(|c| UnicodeWidthChar::width(c).unwrap_or(0))
+        .max()
+        .unwrap_or(0)
+}
+
+// wide clusters will take two units
+fn display_width(clusters: &[&str]) -> usize {
+    clusters.iter()
+        .map(|c| cluster_width(c))
         .fold(0, |acc, n| acc + n)
 }
 
 
 // calculate from left to right, stop when the max_x exceeds
-fn left_fixed(text: &[char], max_x: usize) -> usize {
+fn left_fixed(clusters: &[&str], max_x: usize) -> usize {
     if max_x <= 0 {
         return 0;
     }
 
     let mut w = 0;
-    for (idx, &c) in text.iter().enumerate() {
-        w += if c.len_utf8() > 1 {2} else {1};
+    for (idx, c) in clusters.iter().enumerate() {
+        w += cluster_width(c);
         if w > max_x {
-            return idx-1;
+            return if idx == 0 {0} else {idx-1};
         }
     }
-    return text.len()-1;
+    return clusters.len()-1;
 }
 
-fn right_fixed(text: &[char], max_x: usize) -> usize {
+fn right_fixed(clusters: &[&str], max_x: usize) -> usize {
     if max_x <= 0 {
-        return text.len()-1;
+        return clusters.len()-1;
     }
 
     let mut w = 0;
-    for (idx, &c) in text.iter().enumerate().rev() {
-        w += if c.len_utf8() > 1 {2} else {1};
+    for (idx, c) in clusters.iter().enumerate().rev() {
+        w += cluster_width(c);
         if w > max_x {
             return idx+1;
         }
@@ -427,85 +493,142 @@ fn right_fixed(text: &[char], max_x: usize) -> usize {
 
 }
 
-// return a string and its left position in original string
-// matched_end_pos is char-wise
-fn reshape_string(text: &Vec<char>,
+// return a string (as a list of grapheme clusters) and its left position in the
+// original cluster list. `matched_end_pos` is cluster-wise, not byte/char-wise, so a
+// wide char or a combining sequence is never split by the `..` ellipsis.
+fn reshape_string(clusters: &[&str],
                   container_width: usize,
                   text_start_pos: usize,
-                  matched_end_pos: usize) -> (Vec<char>, usize) {
-    let full_width = display_width(&text[text_start_pos..]);
+                  matched_end_pos: usize) -> (Vec<String>, usize) {
+    let full_width = display_width(&clusters[text_start_pos..]);
 
     if full_width <= container_width {
-        return (text[text_start_pos..].iter().map(|x| *x).collect(), text_start_pos);
+        return (clusters[text_start_pos..].iter().map(|x| x.to_string()).collect(), text_start_pos);
     }
 
     let mut ret = Vec::new();
     let mut ret_pos = 0;
 
     // trim right, so that 'String' -> 'Str..'
-    let right_pos = 1 + max(matched_end_pos, text_start_pos + left_fixed(&text[text_start_pos..], container_width-2));
-    let mut left_pos = text_start_pos + right_fixed(&text[text_start_pos..right_pos], container_width-2);
+    let right_pos = 1 + max(matched_end_pos, text_start_pos + left_fixed(&clusters[text_start_pos..], container_width-2));
+    let mut left_pos = text_start_pos + right_fixed(&clusters[text_start_pos..right_pos], container_width-2);
     ret_pos = left_pos;
 
     if left_pos > text_start_pos {
-        left_pos = text_start_pos + right_fixed(&text[text_start_pos..right_pos], container_width-4);
-        ret.push('.'); ret.push('.');
+        left_pos = text_start_pos + right_fixed(&clusters[text_start_pos..right_pos], container_width-4);
+        ret.push(".".to_string()); ret.push(".".to_string());
         ret_pos = left_pos - 2;
     }
 
     // so we should print [left_pos..(right_pos+1)]
-    for ch in text[left_pos..right_pos].iter() {
-        ret.push(*ch);
+    for cluster in clusters[left_pos..right_pos].iter() {
+        ret.push(cluster.to_string());
     }
-    ret.push('.'); ret.push('.');
+    ret.push(".".to_string()); ret.push(".".to_string());
     (ret, ret_pos)
 }
 
 #[cfg(test)]
 mod test {
+    use unicode_segmentation::UnicodeSegmentation;
+
+    // helper: split into grapheme clusters, one owned String per cluster
+    fn clusters(s: &str) -> Vec<String> {
+        s.graphemes(true).map(|c| c.to_string()).collect()
+    }
+
+    fn cluster_refs(strings: &[String]) -> Vec<&str> {
+        strings.iter().map(|s| s.as_str()).collect()
+    }
+
+    // helper: concatenate a row's cell characters back into a plain string
+    fn row_text(row: &super::Row) -> String {
+        row.iter().map(|c| c.ch.clone()).collect()
+    }
+
     #[test]
     fn test_display_width() {
-        assert_eq!(super::display_width(&"abcdefg".to_string().chars().collect::<Vec<char>>()), 7);
-        assert_eq!(super::display_width(&"This is 中国".to_string().chars().collect::<Vec<char>>()), 12);
+        assert_eq!(super::display_width(&cluster_refs(&clusters("abcdefg"))), 7);
+        assert_eq!(super::display_width(&cluster_refs(&clusters("This is 中国"))), 12);
+    }
+
+    #[test]
+    fn test_display_width_combining_mark() {
+        // "e" + U+0301 (combining acute accent) is a single grapheme cluster and
+        // should be counted as width 1, not 2.
+        let combined = clusters("e\u{301}");
+        assert_eq!(combined.len(), 1);
+        assert_eq!(super::display_width(&cluster_refs(&combined)), 1);
     }
 
     #[test]
     fn test_left_fixed() {
-        assert_eq!(super::left_fixed(&"a中cdef".to_string().chars().collect::<Vec<char>>(), 5), 3);
-        assert_eq!(super::left_fixed(&"a中".to_string().chars().collect::<Vec<char>>(), 5), 1);
-        assert_eq!(super::left_fixed(&"a中".to_string().chars().collect::<Vec<char>>(), 0), 0);
+        assert_eq!(super::left_fixed(&cluster_refs(&clusters("a中cdef")), 5), 3);
+        assert_eq!(super::left_fixed(&cluster_refs(&clusters("a中")), 5), 1);
+        assert_eq!(super::left_fixed(&cluster_refs(&clusters("a中")), 0), 0);
     }
 
     #[test]
     fn test_right_fixed() {
-        assert_eq!(super::right_fixed(&"a中cdef".to_string().chars().collect::<Vec<char>>(), 5), 2);
-        assert_eq!(super::right_fixed(&"a中".to_string().chars().collect::<Vec<char>>(), 5), 0);
-        assert_eq!(super::right_fixed(&"a中".to_string().chars().collect::<Vec<char>>(), 0), 1);
+        assert_eq!(super::right_fixed(&cluster_refs(&clusters("a中cdef")), 5), 2);
+        assert_eq!(super::right_fixed(&cluster_refs(&clusters("a中")), 5), 0);
+        assert_eq!(super::right_fixed(&cluster_refs(&clusters("a中")), 0), 1);
     }
 
     #[test]
     fn test_reshape_string() {
-        assert_eq!(super::reshape_string(&"0123456789".to_string().chars().collect::<Vec<char>>(),
+        assert_eq!(super::reshape_string(&cluster_refs(&clusters("0123456789")),
                                          6, 1, 7),
-                   ("..67..".to_string().chars().collect::<Vec<char>>(), 4));
+                   (clusters("..67.."), 4));
 
-        assert_eq!(super::reshape_string(&"0123456789".to_string().chars().collect::<Vec<char>>(),
+        assert_eq!(super::reshape_string(&cluster_refs(&clusters("0123456789")),
                                          12, 1, 7),
-                   ("123456789".to_string().chars().collect::<Vec<char>>(), 1));
+                   (clusters("123456789"), 1));
 
-        assert_eq!(super::reshape_string(&"0123456789".to_string().chars().collect::<Vec<char>>(),
+        assert_eq!(super::reshape_string(&cluster_refs(&clusters("0123456789")),
                                          6, 0, 6),
-                   ("..56..".to_string().chars().collect::<Vec<char>>(), 3));
+                   (clusters("..56.."), 3));
 
-        assert_eq!(super::reshape_string(&"0123456789".to_string().chars().collect::<Vec<char>>(),
+        assert_eq!(super::reshape_string(&cluster_refs(&clusters("0123456789")),
                                          8, 0, 4),
-                   ("012345..".to_string().chars().collect::<Vec<char>>(), 0));
+                   (clusters("012345.."), 0));
 
-        assert_eq!(super::reshape_string(&"0123456789".to_string().chars().collect::<Vec<char>>(),
+        assert_eq!(super::reshape_string(&cluster_refs(&clusters("0123456789")),
                                          10, 0, 4),
-                   ("0123456789".to_string().chars().collect::<Vec<char>>(), 0));
+                   (clusters("0123456789"), 0));
     }
 
+    #[test]
+    fn test_build_info_row() {
+        assert_eq!(row_text(&super::build_info_row(3, 10, 100)), "  3/10 ");
+        assert_eq!(row_text(&super::build_info_row(3, 10, 42)), "  3/10(42%) ");
+    }
+
+    #[test]
+    fn test_build_query_row() {
+        assert_eq!(row_text(&super::build_query_row("abc")), "> abc");
+    }
+
+    #[test]
+    fn test_build_item_row_markers() {
+        let row = super::build_item_row(true, false, "hello", None, 0, 20, 8);
+        assert_eq!(row[0].ch, ">");
+        assert_eq!(row[1].ch, " ");
+
+        let row = super::build_item_row(false, true, "hello", None, 0, 20, 8);
+        assert_eq!(row[0].ch, " ");
+        assert_eq!(row[1].ch, ">");
+    }
 
+    #[test]
+    fn test_build_item_row_highlights_matched_clusters() {
+        let row = super::build_item_row(false, false, "hello", Some(&[0, 4]), 0, 20, 8);
+        // cells 0 and 1 are the line/selection markers, the text starts at cell 2
+        assert_eq!(row[2].ch, "h");
+        assert_eq!(row[2].role, super::ColorRole::Matched);
+        assert_eq!(row[3].role, super::ColorRole::Normal);
+        assert_eq!(row[6].ch, "o");
+        assert_eq!(row[6].role, super::ColorRole::Matched);
+    }
 
 }
@@ -0,0 +1,118 @@
+/// A pure, backend-independent description of what `Model` wants drawn.
+///
+/// `Model::render` builds a `Screen` from its own state with no ncurses calls
+/// at all, so the layout math (reshaping, highlight positions, the info and
+/// query lines) can be covered by plain unit tests. `Curses::draw` is the
+/// only place left that turns a `Screen` into actual terminal output, which
+/// also means a different backend (termion, crossterm, ...) only needs to
+/// implement that one method.
+
+use ncurses::*;
+use curses::Curses;
+use theme::{ColorRole, ColorTheme};
+
+/// A single cell of the screen: one grapheme cluster plus how it should be
+/// styled, and the terminal column it starts at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cell {
+    pub ch: String,
+    pub role: ColorRole,
+    pub bold: bool,
+    pub col: usize,
+}
+
+/// One row of the screen, left to right. Rows are sparse: a row with no
+/// cells is simply left untouched when drawn.
+pub type Row = Vec<Cell>;
+
+/// Where the terminal cursor should end up after drawing, in (y, x) coordinates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CursorPos {
+    pub y: i32,
+    pub x: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Screen {
+    pub rows: Vec<Row>,
+    pub cursor: Option<CursorPos>,
+}
+
+impl Screen {
+    pub fn new(height: usize) -> Self {
+        Screen {
+            rows: vec![Vec::new(); height],
+            cursor: None,
+        }
+    }
+}
+
+// A small helper for building a `Row` left to right, tracking the terminal
+// column as it goes so that wide clusters don't throw off later cells.
+pub struct RowBuilder {
+    cells: Vec<Cell>,
+    col: usize,
+}
+
+impl Default for RowBuilder {
+    fn default() -> Self {
+        RowBuilder { cells: Vec::new(), col: 0 }
+    }
+}
+
+impl RowBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn push(&mut self, ch: &str, role: ColorRole, bold: bool, width: usize) {
+        self.cells.push(Cell { ch: ch.to_string(), role: role, bold: bold, col: self.col });
+        self.col += width;
+    }
+
+    pub fn col(&self) -> usize {
+        self.col
+    }
+
+    pub fn into_row(self) -> Row {
+        self.cells
+    }
+}
+
+impl Curses {
+    /// Draws a `Screen` produced by `Model::render`. Colors are resolved
+    /// through `theme`, which lazily allocates the underlying ncurses pairs
+    /// (and, for truecolor specs on a terminal that can redefine colors,
+    /// `init_color`s their colors) the first time each role is drawn.
+    pub fn draw(&self, screen: &Screen, theme: &ColorTheme) {
+        erase();
+
+        for (y, row) in screen.rows.iter().enumerate() {
+            for cell in row.iter() {
+                mv(y as i32, cell.col as i32);
+                self.cprint(&cell.ch, theme.pair(cell.role), cell.bold);
+            }
+        }
+
+        if let Some(pos) = screen.cursor {
+            mv(pos.y, pos.x);
+        }
+
+        refresh();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_row_builder_tracks_column_by_width() {
+        let mut row = RowBuilder::new();
+        row.push("中", ColorRole::Normal, false, 2);
+        row.push("a", ColorRole::Normal, false, 1);
+        let cells = row.into_row();
+        assert_eq!(cells[0].col, 0);
+        assert_eq!(cells[1].col, 2);
+    }
+}
@@ -0,0 +1,214 @@
+/// Color theme configuration for the renderer.
+///
+/// Terminal emulators keep color configuration separate from the content they
+/// draw; skim does the same by remapping each rendering role to a color
+/// through a `ColorTheme`, instead of having the renderer hard-code ncurses
+/// color pairs.
+
+use ncurses::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// A rendering role that the UI assigns a color to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColorRole {
+    Cursor,
+    Selected,
+    Matched,
+    Current,
+    Normal,
+}
+
+/// A single color, as named on the `--color` flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpec {
+    /// One of the 8 standard ncurses colors (`COLOR_BLACK` .. `COLOR_WHITE`).
+    /// The bright variants are parsed as `Palette(8..=15)`.
+    Standard(i16),
+    /// A 256-color palette index.
+    Palette(u8),
+    /// A 24-bit truecolor RGB triple, e.g. from `#ff8800`.
+    Rgb(u8, u8, u8),
+}
+
+/// Maps each `ColorRole` to a `ColorSpec`, and lazily turns that mapping into
+/// ncurses color pairs the first time each role is drawn.
+pub struct ColorTheme {
+    specs: HashMap<ColorRole, ColorSpec>,
+    pairs: RefCell<HashMap<ColorRole, i16>>,
+    next_pair: RefCell<i16>,
+    next_color: RefCell<i16>,
+}
+
+impl Default for ColorTheme {
+    fn default() -> Self {
+        let mut specs = HashMap::new();
+        specs.insert(ColorRole::Cursor, ColorSpec::Standard(COLOR_YELLOW));
+        specs.insert(ColorRole::Selected, ColorSpec::Standard(COLOR_MAGENTA));
+        specs.insert(ColorRole::Matched, ColorSpec::Standard(COLOR_GREEN));
+        specs.insert(ColorRole::Current, ColorSpec::Standard(COLOR_CYAN));
+        specs.insert(ColorRole::Normal, ColorSpec::Standard(COLOR_WHITE));
+
+        ColorTheme {
+            specs: specs,
+            pairs: RefCell::new(HashMap::new()),
+            next_pair: RefCell::new(1),
+            next_color: RefCell::new(16), // leave 0..15 as the standard/bright palette
+        }
+    }
+}
+
+impl ColorTheme {
+    /// Parse a `--color` spec string, e.g.
+    /// `matched:bright-green,current:234,selected:#ff8800`, layering it on top
+    /// of `base`. Roles not mentioned in `spec` keep `base`'s color.
+    pub fn parse(spec: &str, base: ColorTheme) -> Result<ColorTheme, String> {
+        let mut theme = base;
+        for entry in spec.split(',') {
+            let entry = entry.trim();
+            if entry.is_empty() {
+                continue;
+            }
+
+            let mut parts = entry.splitn(2, ':');
+            let role_name = parts.next().unwrap_or("");
+            let color_name = match parts.next() {
+                Some(c) => c,
+                None => return Err(format!("invalid color spec: `{}`", entry)),
+            };
+
+            let role = parse_role(role_name)?;
+            let color = parse_color(color_name)?;
+            theme.specs.insert(role, color);
+        }
+        Ok(theme)
+    }
+
+    /// Resolve `role` to an ncurses color-pair id, allocating the pair (and,
+    /// for truecolor specs, `init_color`-ing its color when the terminal can
+    /// redefine colors) the first time it's needed. Palette specs use the
+    /// palette index directly.
+    pub fn pair(&self, role: ColorRole) -> i16 {
+        if let Some(&pair) = self.pairs.borrow().get(&role) {
+            return pair;
+        }
+
+        let spec = *self.specs.get(&role).unwrap_or(&ColorSpec::Standard(COLOR_WHITE));
+        let fg = match spec {
+            ColorSpec::Standard(c) => c,
+            ColorSpec::Palette(idx) => idx as i16,
+            ColorSpec::Rgb(r, g, b) => self.alloc_truecolor(r, g, b),
+        };
+
+        let pair_id = {
+            let mut next_pair = self.next_pair.borrow_mut();
+            let id = *next_pair;
+            *next_pair += 1;
+            id
+        };
+        init_pair(pair_id, fg, COLOR_BLACK);
+        self.pairs.borrow_mut().insert(role, pair_id);
+        pair_id
+    }
+
+    // Allocate a truecolor entry via `init_color`, falling back to the
+    // nearest of the 256-color palette when the terminal doesn't support
+    // redefining colors (`can_change_color()` is false).
+    fn alloc_truecolor(&self, r: u8, g: u8, b: u8) -> i16 {
+        if !can_change_color() {
+            return nearest_256(r, g, b);
+        }
+
+        let mut next_color = self.next_color.borrow_mut();
+        let color_id = *next_color;
+        *next_color += 1;
+        // ncurses' init_color takes components in the 0-1000 range.
+        init_color(color_id, scale(r), scale(g), scale(b));
+        color_id
+    }
+}
+
+fn scale(component: u8) -> i16 {
+    (component as i32 * 1000 / 255) as i16
+}
+
+// Map an RGB triple onto the 256-color palette's 6x6x6 color cube (indices 16-231),
+// whose per-component levels are 0, 95, 135, 175, 215 and 255.
+fn nearest_256(r: u8, g: u8, b: u8) -> i16 {
+    let to_cube = |c: u8| if c < 48 {0} else if c < 115 {1} else {(c as i16 - 35) / 40};
+    16 + 36 * to_cube(r) + 6 * to_cube(g) + to_cube(b)
+}
+
+fn parse_role(name: &str) -> Result<ColorRole, String> {
+    match name {
+        "cursor" => Ok(ColorRole::Cursor),
+        "selected" => Ok(ColorRole::Selected),
+        "matched" => Ok(ColorRole::Matched),
+        "current" => Ok(ColorRole::Current),
+        "normal" => Ok(ColorRole::Normal),
+        _ => Err(format!("unknown color role: `{}`", name)),
+    }
+}
+
+fn parse_color(value: &str) -> Result<ColorSpec, String> {
+    if value.starts_with('#') && value.len() == 7 {
+        let invalid = || format!("invalid hex color: `{}`", value);
+        let r = u8::from_str_radix(&value[1..3], 16).map_err(|_| invalid())?;
+        let g = u8::from_str_radix(&value[3..5], 16).map_err(|_| invalid())?;
+        let b = u8::from_str_radix(&value[5..7], 16).map_err(|_| invalid())?;
+        return Ok(ColorSpec::Rgb(r, g, b));
+    }
+
+    if let Ok(idx) = value.parse::<u8>() {
+        return Ok(ColorSpec::Palette(idx));
+    }
+
+    match value {
+        "black" => Ok(ColorSpec::Standard(COLOR_BLACK)),
+        "red" => Ok(ColorSpec::Standard(COLOR_RED)),
+        "green" => Ok(ColorSpec::Standard(COLOR_GREEN)),
+        "yellow" => Ok(ColorSpec::Standard(COLOR_YELLOW)),
+        "blue" => Ok(ColorSpec::Standard(COLOR_BLUE)),
+        "magenta" => Ok(ColorSpec::Standard(COLOR_MAGENTA)),
+        "cyan" => Ok(ColorSpec::Standard(COLOR_CYAN)),
+        "white" => Ok(ColorSpec::Standard(COLOR_WHITE)),
+        "bright-black" => Ok(ColorSpec::Palette(8)),
+        "bright-red" => Ok(ColorSpec::Palette(9)),
+        "bright-green" => Ok(ColorSpec::Palette(10)),
+        "bright-yellow" => Ok(ColorSpec::Palette(11)),
+        "bright-blue" => Ok(ColorSpec::Palette(12)),
+        "bright-magenta" => Ok(ColorSpec::Palette(13)),
+        "bright-cyan" => Ok(ColorSpec::Palette(14)),
+        "bright-white" => Ok(ColorSpec::Palette(15)),
+        _ => Err(format!("unknown color: `{}`", value)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_named_and_palette() {
+        let theme = ColorTheme::parse("matched:bright-green,current:234", ColorTheme::default()).unwrap();
+        assert_eq!(*theme.specs.get(&ColorRole::Matched).unwrap(), ColorSpec::Palette(10));
+        assert_eq!(*theme.specs.get(&ColorRole::Current).unwrap(), ColorSpec::Palette(234));
+    }
+
+    #[test]
+    fn test_parse_rgb() {
+        let theme = ColorTheme::parse("selected:#ff8800", ColorTheme::default()).unwrap();
+        assert_eq!(*theme.specs.get(&ColorRole::Selected).unwrap(), ColorSpec::Rgb(0xff, 0x88, 0x00));
+    }
+
+    #[test]
+    fn test_parse_unknown_role_is_error() {
+        assert!(ColorTheme::parse("blink:red", ColorTheme::default()).is_err());
+    }
+
+    #[test]
+    fn test_parse_unmentioned_role_keeps_base() {
+        let theme = ColorTheme::parse("matched:red", ColorTheme::default()).unwrap();
+        assert_eq!(*theme.specs.get(&ColorRole::Normal).unwrap(), ColorSpec::Standard(COLOR_WHITE));
+    }
+}